@@ -3,13 +3,58 @@
 
 use hir_expand::name::Name;
 use once_cell::sync::Lazy;
-use rustc_hash::FxHashMap;
+use ra_prof::Count;
+use rustc_hash::{FxHashMap, FxHashSet};
+use smallvec::SmallVec;
 
-use crate::{per_ns::PerNs, AdtId, BuiltinType, ImplId, MacroDefId, ModuleDefId, TraitId};
+use crate::{
+    db::DefDatabase, per_ns::PerNs, visibility::Visibility, AdtId, BuiltinType, ImplId,
+    LocalModuleId, MacroDefId, ModuleDefId, ModuleId, TraitId,
+};
 
-#[derive(Debug, Default, PartialEq, Eq)]
+/// How a name became visible in a scope. A `Named` import or a local
+/// declaration shadows a `Glob` import of the same name.
+#[derive(Copy, Clone)]
+pub(crate) enum ImportType {
+    Glob,
+    Named,
+}
+
+/// Namespace a glob-import ambiguity was recorded in, so the diagnostics layer
+/// can point at the right resolution when emitting "ambiguous glob import".
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum GlobNs {
+    Types,
+    Values,
+    Macros,
+}
+
+/// Records which currently-visible names arrived through a glob import, per
+/// namespace. Maintained alongside the `ItemScope`s by the def collector so a
+/// later named import (or local declaration) can override a glob entry.
+#[derive(Debug, Default)]
+pub struct PerNsGlobImports {
+    types: FxHashSet<(LocalModuleId, Name)>,
+    values: FxHashSet<(LocalModuleId, Name)>,
+    macros: FxHashSet<(LocalModuleId, Name)>,
+}
+
+#[derive(Debug, Default)]
 pub struct ItemScope {
-    visible: FxHashMap<Name, PerNs>,
+    /// Bumps a global live-instance counter on construction and drop so
+    /// maintainers can measure how many scopes def-collection produces.
+    /// Compiles out when the `cpu_profiler`/counting feature is off.
+    _c: Count<Self>,
+
+    types: FxHashMap<Name, (ModuleDefId, Visibility)>,
+    values: FxHashMap<Name, (ModuleDefId, Visibility)>,
+    macros: FxHashMap<Name, (MacroDefId, Visibility)>,
+    unresolved: FxHashSet<Name>,
+    /// Names that two distinct glob imports resolved to *different* defs in the
+    /// same namespace. Exposed via `ambiguous_glob_imports` so the diagnostics
+    /// layer can emit an "ambiguous glob import" warning.
+    ambiguous_globs: FxHashSet<(Name, GlobNs)>,
+
     defs: Vec<ModuleDefId>,
     impls: Vec<ImplId>,
     /// Macros visible in current module in legacy textual scope
@@ -22,11 +67,31 @@ pub struct ItemScope {
     /// Note that this automatically inherit macros defined textually before the definition of module itself.
     ///
     /// Module scoped macros will be inserted into `items` instead of here.
-    // FIXME: Macro shadowing in one module is not properly handled. Non-item place macros will
-    // be all resolved to the last one defined if shadowing happens.
-    legacy_macros: FxHashMap<Name, MacroDefId>,
+    ///
+    /// Each name keeps the full ordered history of `macro_rules!` definitions
+    /// with that name, so a textual invocation can resolve against the
+    /// definition in effect at its position rather than always the last one.
+    legacy_macros: FxHashMap<Name, SmallVec<[MacroDefId; 1]>>,
 }
 
+// `ra_prof::Count` has no `PartialEq`/`Eq` (it only tracks a live-instance
+// counter and has a manual `Drop`), so these cannot be derived. Compare every
+// semantic field and ignore the instrumentation one.
+impl PartialEq for ItemScope {
+    fn eq(&self, other: &Self) -> bool {
+        self.types == other.types
+            && self.values == other.values
+            && self.macros == other.macros
+            && self.unresolved == other.unresolved
+            && self.ambiguous_globs == other.ambiguous_globs
+            && self.defs == other.defs
+            && self.impls == other.impls
+            && self.legacy_macros == other.legacy_macros
+    }
+}
+
+impl Eq for ItemScope {}
+
 static BUILTIN_SCOPE: Lazy<FxHashMap<Name, PerNs>> = Lazy::new(|| {
     BuiltinType::ALL
         .iter()
@@ -48,7 +113,14 @@ pub(crate) enum BuiltinShadowMode {
 impl ItemScope {
     pub fn entries<'a>(&'a self) -> impl Iterator<Item = (&'a Name, PerNs)> + 'a {
         //FIXME: shadowing
-        self.visible.iter().chain(BUILTIN_SCOPE.iter()).map(|(n, def)| (n, *def))
+        let mut seen = FxHashSet::default();
+        self.types
+            .keys()
+            .chain(self.values.keys())
+            .chain(self.macros.keys())
+            .filter(move |name| seen.insert(*name))
+            .map(move |name| (name, self.get(name)))
+            .chain(BUILTIN_SCOPE.iter().map(|(name, def)| (name, *def)))
     }
 
     pub fn declarations(&self) -> impl Iterator<Item = ModuleDefId> + '_ {
@@ -61,44 +133,118 @@ impl ItemScope {
 
     /// Iterate over all module scoped macros
     pub(crate) fn macros<'a>(&'a self) -> impl Iterator<Item = (&'a Name, MacroDefId)> + 'a {
-        self.visible.iter().filter_map(|(name, def)| def.take_macros().map(|macro_| (name, macro_)))
+        self.macros.iter().map(|(name, def)| (name, def.0))
     }
 
-    /// Iterate over all legacy textual scoped macros visible at the end of the module
-    pub(crate) fn legacy_macros<'a>(&'a self) -> impl Iterator<Item = (&'a Name, MacroDefId)> + 'a {
-        self.legacy_macros.iter().map(|(name, def)| (name, *def))
+    /// Iterate over all legacy textual scoped macros visible at the end of the
+    /// module, exposing the full per-name definition chain for block and
+    /// nested-module inheritance.
+    pub(crate) fn legacy_macros<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = (&'a Name, &'a [MacroDefId])> + 'a {
+        self.legacy_macros.iter().map(|(name, def)| (name, def.as_slice()))
     }
 
     /// Get a name from current module scope, legacy macros are not included
-    pub(crate) fn get(&self, name: &Name, shadow: BuiltinShadowMode) -> Option<&PerNs> {
+    pub(crate) fn get(&self, name: &Name) -> PerNs {
+        PerNs {
+            types: self.types.get(name).map(|(def, _)| *def),
+            values: self.values.get(name).map(|(def, _)| *def),
+            macros: self.macros.get(name).map(|(def, _)| *def),
+        }
+    }
+
+    /// Get a name from current module scope, respecting visibility: entries that
+    /// are not reachable from `from_module` are filtered out of each namespace.
+    pub(crate) fn get_visible(
+        &self,
+        db: &dyn DefDatabase,
+        name: &Name,
+        from_module: ModuleId,
+    ) -> Option<PerNs> {
+        // The three maps hold different def types (`ModuleDefId` vs
+        // `MacroDefId`), so a single closure over the whole entry can't be
+        // shared; filter on the `Visibility` alone and let each field infer its
+        // own def type, mirroring `get` above.
+        let reachable = |vis: &Visibility| vis.is_visible_from(db, from_module);
+        let res = PerNs {
+            types: self.types.get(name).filter(|(_, vis)| reachable(vis)).map(|(def, _)| *def),
+            values: self.values.get(name).filter(|(_, vis)| reachable(vis)).map(|(def, _)| *def),
+            macros: self.macros.get(name).filter(|(_, vis)| reachable(vis)).map(|(def, _)| *def),
+        };
+        if res.is_none() {
+            None
+        } else {
+            Some(res)
+        }
+    }
+
+    pub(crate) fn get_with_shadow(&self, name: &Name, shadow: BuiltinShadowMode) -> PerNs {
+        let from_scope = self.get(name);
+        let from_builtin = BUILTIN_SCOPE.get(name).copied();
+
         match shadow {
-            BuiltinShadowMode::Module => self.visible.get(name).or_else(|| BUILTIN_SCOPE.get(name)),
+            BuiltinShadowMode::Module => {
+                if from_scope.is_none() {
+                    from_builtin.unwrap_or(from_scope)
+                } else {
+                    from_scope
+                }
+            }
             BuiltinShadowMode::Other => {
-                let item = self.visible.get(name);
-                if let Some(def) = item {
-                    if let Some(ModuleDefId::ModuleId(_)) = def.take_types() {
-                        return BUILTIN_SCOPE.get(name).or(item);
-                    }
+                if let Some(ModuleDefId::ModuleId(_)) = from_scope.types {
+                    from_builtin.unwrap_or(from_scope)
+                } else if from_scope.is_none() {
+                    from_builtin.unwrap_or(from_scope)
+                } else {
+                    from_scope
                 }
-
-                item.or_else(|| BUILTIN_SCOPE.get(name))
             }
         }
     }
 
     pub(crate) fn traits<'a>(&'a self) -> impl Iterator<Item = TraitId> + 'a {
-        self.visible.values().filter_map(|def| match def.take_types() {
-            Some(ModuleDefId::TraitId(t)) => Some(t),
+        self.types.values().filter_map(|(def, _)| match def {
+            ModuleDefId::TraitId(t) => Some(*t),
             _ => None,
         })
     }
 
+    /// Iterate over names whose `use` / path failed to resolve.
+    pub(crate) fn unresolved(&self) -> impl Iterator<Item = &Name> + '_ {
+        self.unresolved.iter()
+    }
+
+    /// Iterate over `(name, namespace)` pairs that two distinct glob imports
+    /// resolved to conflicting defs, so the diagnostics layer can emit an
+    /// "ambiguous glob import" warning for each.
+    pub(crate) fn ambiguous_glob_imports(&self) -> impl Iterator<Item = (&Name, GlobNs)> + '_ {
+        self.ambiguous_globs.iter().map(|(name, ns)| (name, *ns))
+    }
+
     pub(crate) fn define_def(&mut self, def: ModuleDefId) {
         self.defs.push(def)
     }
 
-    pub(crate) fn get_legacy_macro(&self, name: &Name) -> Option<MacroDefId> {
-        self.legacy_macros.get(name).copied()
+    /// Record that `name` could not be resolved once the resolution fixpoint
+    /// has settled, so the diagnostics layer can turn it into an
+    /// "unresolved import" warning.
+    pub(crate) fn define_unresolved(&mut self, name: Name) {
+        self.unresolved.insert(name);
+    }
+
+    /// Resolve `name` in legacy textual scope to the `macro_rules!` definition
+    /// in effect at an invocation, where `defined_before` is the number of
+    /// same-named definitions that appear textually *before* the invocation.
+    /// The invocation therefore sees the last definition in that prefix rather
+    /// than always the final one, so shadowing matches rustc. Pass
+    /// `usize::MAX` to resolve against the full chain (i.e. the state at the end
+    /// of the module).
+    pub(crate) fn get_legacy_macro(&self, name: &Name, defined_before: usize) -> Option<MacroDefId> {
+        self.legacy_macros.get(name).and_then(|defs| {
+            let prefix = defs.get(..defined_before).unwrap_or(defs);
+            prefix.last().copied()
+        })
     }
 
     pub(crate) fn define_impl(&mut self, imp: ImplId) {
@@ -106,36 +252,141 @@ impl ItemScope {
     }
 
     pub(crate) fn define_legacy_macro(&mut self, name: Name, mac: MacroDefId) {
-        self.legacy_macros.insert(name, mac);
+        self.legacy_macros.entry(name).or_default().push(mac);
     }
 
-    pub(crate) fn push_res(&mut self, name: Name, def: &PerNs) -> bool {
+    /// Insert a resolution for `lookup.1` in this scope, tracking whether it
+    /// arrived through a glob or a named import.
+    ///
+    /// A `Named` import or a local declaration always overrides a glob entry
+    /// for the same name/namespace (and drops it from `glob_imports`). Two
+    /// distinct globs resolving the same name to *different* defs are left
+    /// ambiguous instead of last-write-wins; two globs pointing at the same def
+    /// are fine. Returns whether the scope changed so the fixpoint collector
+    /// can decide to iterate again.
+    pub(crate) fn push_res_with_import(
+        &mut self,
+        db: &dyn DefDatabase,
+        glob_imports: &mut PerNsGlobImports,
+        lookup: (LocalModuleId, Name),
+        def: &PerNs,
+        vis: Visibility,
+        import_type: ImportType,
+    ) -> bool {
+        use std::collections::hash_map::Entry;
+
         let mut changed = false;
-        let existing = self.visible.entry(name.clone()).or_default();
 
-        if existing.types.is_none() && def.types.is_some() {
-            existing.types = def.types;
-            changed = true;
-        }
-        if existing.values.is_none() && def.values.is_some() {
-            existing.values = def.values;
-            changed = true;
-        }
-        if existing.macros.is_none() && def.macros.is_some() {
-            existing.macros = def.macros;
-            changed = true;
+        macro_rules! merge_ns {
+            ($field:ident, $ns:expr) => {{
+                if let Some(def) = def.$field {
+                    match self.$field.entry(lookup.1.clone()) {
+                        Entry::Vacant(entry) => {
+                            match import_type {
+                                ImportType::Glob => {
+                                    glob_imports.$field.insert(lookup.clone());
+                                }
+                                ImportType::Named => {
+                                    glob_imports.$field.remove(&lookup);
+                                }
+                            }
+                            entry.insert((def, vis));
+                            changed = true;
+                        }
+                        Entry::Occupied(mut entry) => {
+                            let was_glob = glob_imports.$field.contains(&lookup);
+                            match import_type {
+                                // A named import / local declaration wins over a glob,
+                                // and also clears any ambiguity recorded for globs.
+                                ImportType::Named if was_glob => {
+                                    glob_imports.$field.remove(&lookup);
+                                    self.ambiguous_globs.remove(&(lookup.1.clone(), $ns));
+                                    entry.insert((def, vis));
+                                    changed = true;
+                                }
+                                // Distinct globs for the same name are ambiguous.
+                                // Two globs pointing at the *same* def are fine, but
+                                // the def may be reachable through a more permissive
+                                // glob path than the one recorded first, so widen the
+                                // stored visibility the same way named-vs-named does.
+                                ImportType::Glob if was_glob => {
+                                    if entry.get().0 != def {
+                                        self.ambiguous_globs.insert((lookup.1.clone(), $ns));
+                                    } else {
+                                        let existing = entry.get().1;
+                                        if vis != existing && vis.max(existing, db) == Some(vis) {
+                                            entry.insert((def, vis));
+                                            changed = true;
+                                        }
+                                    }
+                                }
+                                // Named-vs-named: for the *same* def, widen to the
+                                // more-visible resolution. Distinct defs are a
+                                // duplicate-definition conflict left to first-wins /
+                                // diagnostics, not silently overwritten.
+                                ImportType::Named => {
+                                    let existing = entry.get().1;
+                                    // `max` returns the more permissive of the two
+                                    // visibilities, or `None` if they are incomparable
+                                    // (e.g. two unrelated `pub(in path)`s).
+                                    if entry.get().0 == def
+                                        && vis != existing
+                                        && vis.max(existing, db) == Some(vis)
+                                    {
+                                        entry.insert((def, vis));
+                                        changed = true;
+                                    }
+                                }
+                                // A glob can never override a named import / declaration.
+                                ImportType::Glob => {}
+                            }
+                        }
+                    }
+                }
+            }};
         }
 
+        merge_ns!(types, GlobNs::Types);
+        merge_ns!(values, GlobNs::Values);
+        merge_ns!(macros, GlobNs::Macros);
+
         changed
     }
 
     pub(crate) fn collect_resolutions(&self) -> Vec<(Name, PerNs)> {
-        self.visible.iter().map(|(name, res)| (name.clone(), res.clone())).collect()
+        // Iterate only the real resolution maps, not `entries()`: glob-import
+        // propagation must not pull `BUILTIN_SCOPE` names into the importing
+        // scope.
+        let mut seen = FxHashSet::default();
+        self.types
+            .keys()
+            .chain(self.values.keys())
+            .chain(self.macros.keys())
+            .filter(|name| seen.insert((*name).clone()))
+            .map(|name| (name.clone(), self.get(name)))
+            .collect()
     }
 
-    pub(crate) fn collect_legacy_macros(&self) -> FxHashMap<Name, MacroDefId> {
+    pub(crate) fn collect_legacy_macros(&self) -> FxHashMap<Name, SmallVec<[MacroDefId; 1]>> {
         self.legacy_macros.clone()
     }
+
+    /// Appends one line with the number of `types`, `values`, `macros`, `impls`
+    /// and `defs` entries, for cheap diagnostics of scope-allocation blowups.
+    /// `legacy_macros`, `unresolved` and `ambiguous_globs` are not counted.
+    pub(crate) fn dump(&self, buf: &mut String) {
+        use std::fmt::Write;
+
+        let _ = writeln!(
+            buf,
+            "ItemScope {{ types: {}, values: {}, macros: {}, impls: {}, defs: {} }}",
+            self.types.len(),
+            self.values.len(),
+            self.macros.len(),
+            self.impls.len(),
+            self.defs.len(),
+        );
+    }
 }
 
 impl From<ModuleDefId> for PerNs {
@@ -154,4 +405,4 @@ impl From<ModuleDefId> for PerNs {
             ModuleDefId::BuiltinType(_) => PerNs::types(def),
         }
     }
-}
\ No newline at end of file
+}